@@ -1,108 +1,951 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
 use std::ptr;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use epoch::Collector;
+use notify::Notify;
+
+/// Epoch-based deferred reclamation, used by [`LockFreeQueue`] to free
+/// dequeued nodes only once no other thread can still be dereferencing
+/// them.
+///
+/// The scheme follows the usual epoch-based reclamation recipe (as used by
+/// `crossbeam-epoch`): a global epoch counter advances only once every
+/// registered thread is either unpinned or pinned at the current epoch, and
+/// garbage retired during an epoch is only actually freed once that epoch
+/// is at least two generations in the past, which guarantees every thread
+/// that could have observed the retired pointer has since unpinned.
+mod epoch {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    const EPOCH_COUNT: usize = 3;
+    const PINNED_BIT: usize = 1;
+
+    /// Hands out a unique id to every `Collector` so thread-local caches
+    /// can key on identity rather than address: a dropped collector's
+    /// memory can be reused by a brand new one, and an address-keyed
+    /// cache would then alias a stale registration onto it.
+    static NEXT_COLLECTOR_ID: AtomicU64 = AtomicU64::new(0);
+
+    /// One epoch's worth of retired-but-not-yet-freed allocations.
+    type GarbageList = Mutex<Vec<Box<dyn FnOnce() + Send>>>;
+
+    /// A thread's registration with one `Collector`: its liveness flag
+    /// (shared with the collector, so drop can mark it stale) paired with
+    /// this thread's pin state for that collector.
+    type Registration = (Arc<AtomicBool>, Arc<LocalEpoch>);
+
+    /// A single thread's view of the epoch: unpinned, or pinned at a
+    /// specific epoch while it dereferences shared pointers.
+    struct LocalEpoch {
+        state: AtomicUsize,
+    }
+
+    impl LocalEpoch {
+        fn new() -> Self {
+            LocalEpoch {
+                state: AtomicUsize::new(0),
+            }
+        }
+
+        fn pin(&self, epoch: usize) {
+            self.state.store((epoch << 1) | PINNED_BIT, Ordering::SeqCst);
+        }
+
+        fn unpin(&self) {
+            self.state.store(0, Ordering::Release);
+        }
+
+        fn snapshot(&self) -> Option<usize> {
+            let value = self.state.load(Ordering::Acquire);
+            if value & PINNED_BIT == 0 {
+                None
+            } else {
+                Some(value >> 1)
+            }
+        }
+    }
+
+    thread_local! {
+        // Keyed by the owning `Collector`'s unique id, since a single thread
+        // may be pinning several independent collectors (one per queue) and
+        // a collector's address can be reused by a later instance once
+        // dropped. Each entry also carries the collector's `alive` flag, so
+        // a thread that pins many short-lived collectors over its lifetime
+        // (e.g. one per connection) prunes entries for ones that have since
+        // been dropped instead of accumulating them forever.
+        static REGISTRATIONS: RefCell<HashMap<u64, Registration>> =
+            RefCell::new(HashMap::new());
+    }
+
+    /// Per-instance epoch collector: tracks the global epoch, every
+    /// registered thread's pin state, and the garbage retired per epoch.
+    pub(crate) struct Collector {
+        id: u64,
+        // Shared with every thread-local registration this collector has
+        // accumulated; flipped to `false` on drop so those entries can be
+        // recognized as stale and pruned from `REGISTRATIONS`.
+        alive: Arc<AtomicBool>,
+        global_epoch: AtomicUsize,
+        threads: Mutex<Vec<Arc<LocalEpoch>>>,
+        garbage: [GarbageList; EPOCH_COUNT],
+    }
+
+    impl Collector {
+        pub(crate) fn new() -> Self {
+            Collector {
+                id: NEXT_COLLECTOR_ID.fetch_add(1, Ordering::Relaxed),
+                alive: Arc::new(AtomicBool::new(true)),
+                global_epoch: AtomicUsize::new(0),
+                threads: Mutex::new(Vec::new()),
+                garbage: std::array::from_fn(|_| Mutex::new(Vec::new())),
+            }
+        }
+
+        fn local(&self) -> Arc<LocalEpoch> {
+            let key = self.id;
+            REGISTRATIONS.with(|registrations| {
+                let mut registrations = registrations.borrow_mut();
+                registrations.retain(|_, (alive, _)| alive.load(Ordering::Acquire));
+                registrations
+                    .entry(key)
+                    .or_insert_with(|| {
+                        let local = Arc::new(LocalEpoch::new());
+                        self.threads.lock().unwrap().push(local.clone());
+                        (self.alive.clone(), local)
+                    })
+                    .1
+                    .clone()
+            })
+        }
+
+        /// Pins the calling thread to the current global epoch for the
+        /// duration of the returned [`Guard`].
+        pub(crate) fn pin(&self) -> Guard<'_> {
+            let local = self.local();
+            let epoch = self.global_epoch.load(Ordering::SeqCst);
+            local.pin(epoch);
+            Guard {
+                collector: self,
+                local,
+            }
+        }
+
+        /// Advances the global epoch if every registered thread has been
+        /// observed at the current epoch (or is unpinned), then frees
+        /// garbage old enough that no thread can still reference it.
+        fn try_advance(&self) {
+            let current = self.global_epoch.load(Ordering::SeqCst);
+            {
+                let threads = self.threads.lock().unwrap();
+                for thread in threads.iter() {
+                    if let Some(epoch) = thread.snapshot() {
+                        if epoch != current {
+                            return;
+                        }
+                    }
+                }
+            }
+
+            let next = current.wrapping_add(1);
+            if self
+                .global_epoch
+                .compare_exchange(current, next, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                // Everything retired two epochs ago can no longer be
+                // observed by any participant and is safe to free.
+                let safe_index = next.wrapping_add(1) % EPOCH_COUNT;
+                let mut bucket = self.garbage[safe_index].lock().unwrap();
+                for destroy in bucket.drain(..) {
+                    destroy();
+                }
+            }
+        }
+    }
+
+    /// Number of collector registrations the calling thread currently
+    /// holds, including any for dropped collectors that have not been
+    /// pruned yet. Exposed only to let tests confirm dropped collectors
+    /// actually get pruned instead of leaking.
+    #[cfg(test)]
+    pub(crate) fn registered_thread_count() -> usize {
+        REGISTRATIONS.with(|registrations| registrations.borrow().len())
+    }
+
+    impl Drop for Collector {
+        fn drop(&mut self) {
+            // Marks every thread-local registration for this collector as
+            // stale, so the next `local()` call on each of those threads
+            // (for any collector) prunes it instead of leaking forever.
+            self.alive.store(false, Ordering::Release);
+
+            // No concurrent pinners can exist once the owning queue is
+            // being dropped, so it's safe to free everything outstanding.
+            for bucket in &self.garbage {
+                for destroy in bucket.lock().unwrap().drain(..) {
+                    destroy();
+                }
+            }
+        }
+    }
+
+    /// An RAII guard returned by [`Collector::pin`]; the calling thread is
+    /// considered pinned until the guard is dropped.
+    pub(crate) struct Guard<'c> {
+        collector: &'c Collector,
+        local: Arc<LocalEpoch>,
+    }
+
+    impl<'c> Guard<'c> {
+        /// Schedules an arbitrary closure to run once no pinned thread can
+        /// still be observing whatever it touches, e.g. a node being
+        /// freed or recycled into a pool.
+        ///
+        /// # Safety
+        /// `f` must be safe to run at an arbitrary later point, once every
+        /// currently pinned thread (including the caller) has unpinned at
+        /// least once.
+        pub(crate) unsafe fn defer<F: FnOnce() + Send + 'static>(&self, f: F) {
+            let epoch = self.collector.global_epoch.load(Ordering::SeqCst);
+            self.collector.garbage[epoch % EPOCH_COUNT]
+                .lock()
+                .unwrap()
+                .push(Box::new(f));
+            self.collector.try_advance();
+        }
+    }
+
+    impl<'c> Drop for Guard<'c> {
+        fn drop(&mut self) {
+            self.local.unpin();
+        }
+    }
+}
+
+/// A minimal wait/notify primitive used to let blocking and async
+/// consumers sleep instead of spinning when [`LockFreeQueue`] is empty.
+///
+/// It deliberately does not track "permits" the way e.g. `tokio::Notify`
+/// does: a registered waiter always re-checks the queue itself right
+/// after registering, so a notification racing ahead of the registration
+/// is never actually missed — it just means the subsequent check finds
+/// the data directly instead of needing to be woken at all.
+mod notify {
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::task::Waker;
+    use std::thread::Thread;
+
+    enum Waiter {
+        Thread(Thread),
+        Task(Waker),
+    }
+
+    impl Waiter {
+        fn wake(self) {
+            match self {
+                Waiter::Thread(thread) => thread.unpark(),
+                Waiter::Task(waker) => waker.wake(),
+            }
+        }
+    }
+
+    /// Identifies one registration, so it can be cancelled later.
+    pub(crate) struct Token(u64);
+
+    pub(crate) struct Notify {
+        // Mirrors the length of `waiters` so `notify_one` can skip
+        // locking the mutex entirely when nobody is registered.
+        count: AtomicUsize,
+        next_token: AtomicU64,
+        waiters: Mutex<VecDeque<(u64, Waiter)>>,
+    }
+
+    impl Notify {
+        pub(crate) fn new() -> Self {
+            Notify {
+                count: AtomicUsize::new(0),
+                next_token: AtomicU64::new(0),
+                waiters: Mutex::new(VecDeque::new()),
+            }
+        }
+
+        fn register(&self, waiter: Waiter) -> Token {
+            let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+            self.waiters.lock().unwrap().push_back((token, waiter));
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Token(token)
+        }
+
+        /// Registers the calling thread so a later `notify_one` can
+        /// `unpark` it.
+        pub(crate) fn register_thread(&self) -> Token {
+            self.register(Waiter::Thread(std::thread::current()))
+        }
+
+        /// Registers a `Waker` so a later `notify_one` can wake the task.
+        pub(crate) fn register_waker(&self, waker: &Waker) -> Token {
+            self.register(Waiter::Task(waker.clone()))
+        }
+
+        /// Removes a registration that resolved on its own — the
+        /// caller's own double-check already found data without
+        /// needing to park/poll. Without this, the stale entry would
+        /// just sit in the FIFO `waiters` deque until some later,
+        /// unrelated `notify_one` popped and woke it instead of the
+        /// consumer that's actually still waiting behind it.
+        pub(crate) fn cancel(&self, token: Token) {
+            let mut waiters = self.waiters.lock().unwrap();
+            if let Some(pos) = waiters.iter().position(|(t, _)| *t == token.0) {
+                waiters.remove(pos);
+                drop(waiters);
+                self.count.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        /// Number of currently registered waiters. Exposed only to let
+        /// tests confirm registrations get cancelled rather than leaking.
+        #[cfg(test)]
+        pub(crate) fn waiter_count(&self) -> usize {
+            self.waiters.lock().unwrap().len()
+        }
+
+        /// Wakes a single registered waiter, if any. Cheap (one atomic
+        /// load of `count`, no lock) when nobody is registered.
+        pub(crate) fn notify_one(&self) {
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            let waiter = self.waiters.lock().unwrap().pop_front();
+            if let Some((_, waiter)) = waiter {
+                self.count.fetch_sub(1, Ordering::SeqCst);
+                waiter.wake();
+            }
+        }
+    }
+}
 
-#[derive(Debug)]
 struct Node<T> {
-    data: T,
+    // Read exactly once, by the `take` that makes this node the new
+    // sentinel at `head`; from then on the sentinel's slot has been moved
+    // out of (or, for the initial sentinel, was never initialized), so it
+    // must never be read or dropped again.
+    data: MaybeUninit<T>,
     next: AtomicPtr<Node<T>>,
 }
 
-#[derive(Debug)]
+impl<T> std::fmt::Debug for Node<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Node").field("next", &self.next).finish()
+    }
+}
+
+/// A lock-free Treiber stack of retired `Node<T>` allocations, reused by
+/// `offer` instead of going back to the global allocator on every push.
+///
+/// A node only ever reaches the pool after the epoch collector has
+/// confirmed no thread can still be dereferencing it as a live queue node,
+/// so recycling it here is free of the use-after-free hazard that would
+/// come from pooling nodes still reachable through `head`/`tail`.
+struct Pool<T> {
+    // A single-slot fast path for the top of the stack: under the common
+    // case of one node being retired and reused shortly after, this lets
+    // `push`/`pop` exchange it with an unconditional atomic swap instead of
+    // walking the CAS-retry loop below. It's the same swap `AtomicOptionBox`
+    // exists to provide, so reuse it here rather than hand-rolling another
+    // single-slot swap.
+    fast: AtomicOptionBox<Node<T>>,
+    head: AtomicPtr<Node<T>>,
+}
+
+impl<T> Pool<T> {
+    fn new() -> Self {
+        Pool {
+            fast: AtomicOptionBox::new(None),
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Returns a retired node to the pool, to be handed back out by `pop`.
+    ///
+    /// # Safety
+    /// The fallback Treiber stack below the fast slot is vulnerable to the
+    /// classic ABA hazard: a node this call links back on top can already
+    /// be the exact node a concurrent `pop` read as `head` before this call
+    /// started, in which case that `pop`'s compare-and-swap would succeed
+    /// against a `next` this call just replaced. The only reason that's not
+    /// reachable in practice is that every caller, transitively, only ever
+    /// invokes `push` from inside a closure scheduled via the owning
+    /// `LockFreeQueue`'s `Guard::defer` — which guarantees two full epoch
+    /// generations have passed, and therefore that any thread pinned when
+    /// the node was retired (including one paused mid-`pop`) has long since
+    /// unpinned and completed its compare-and-swap. Calling this from
+    /// anywhere else reopens that hazard.
+    unsafe fn push(&self, node: *mut Node<T>) {
+        let boxed = unsafe { Box::from_raw(node) };
+        let Some(evicted) = self.fast.swap(Some(boxed)) else {
+            return;
+        };
+        // The fast slot was already occupied; fall back to pushing the
+        // node it held onto the full Treiber stack.
+        let evicted = Box::into_raw(evicted);
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            unsafe {
+                (*evicted).next.store(head, Ordering::Relaxed);
+            }
+            match self
+                .head
+                .compare_exchange_weak(head, evicted, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Takes a node out of the pool, if one is available.
+    ///
+    /// `_guard` isn't read; it exists so the call site has to hold a pin on
+    /// the same collector that every `push` is deferred against (see its
+    /// safety comment), which is what keeps this CAS loop's stale `next`
+    /// reads from ever racing a node's return trip back onto the stack.
+    fn pop(&self, _guard: &epoch::Guard<'_>) -> Option<*mut Node<T>> {
+        if let Some(boxed) = self.fast.take() {
+            return Some(Box::into_raw(boxed));
+        }
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            if head.is_null() {
+                return None;
+            }
+            let next = unsafe { (*head).next.load(Ordering::Acquire) };
+            match self
+                .head
+                .compare_exchange_weak(head, next, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return Some(head),
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+impl<T> Drop for Pool<T> {
+    fn drop(&mut self) {
+        // `&mut self` proves no concurrent pusher/popper exists, so the
+        // free list can just be walked and freed directly.
+        if let Some(boxed) = self.fast.take() {
+            drop(boxed);
+        }
+        let mut node = *self.head.get_mut();
+        while !node.is_null() {
+            unsafe {
+                let next = *(*node).next.get_mut();
+                drop(Box::from_raw(node));
+                node = next;
+            }
+        }
+    }
+}
+
 pub struct LockFreeQueue<T> {
     head: AtomicPtr<Node<T>>,
-    tail: AtomicPtr<Node<T>>
+    tail: AtomicPtr<Node<T>>,
+    collector: Collector,
+    pool: Pool<T>,
+    notify: Notify,
+}
+
+impl<T> std::fmt::Debug for LockFreeQueue<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LockFreeQueue")
+            .field("head", &self.head)
+            .field("tail", &self.tail)
+            .finish()
+    }
 }
 
-impl<T: std::default::Default> LockFreeQueue<T> {
+impl<T> Default for LockFreeQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> LockFreeQueue<T> {
 
     pub fn new() -> Self {
         let dummy_node = Box::into_raw(Box::new(Node {
-            data: Default::default(),
+            data: MaybeUninit::uninit(),
             next: AtomicPtr::new(ptr::null_mut()),
         }));
 
         LockFreeQueue {
             head: AtomicPtr::new(dummy_node),
             tail: AtomicPtr::new(dummy_node),
+            collector: Collector::new(),
+            pool: Pool::new(),
+            notify: Notify::new(),
         }
     }
 
     pub fn offer(&self, data: T) {
-        let new_node = Box::into_raw(Box::new(Node {
-            data,
-            next: AtomicPtr::new(ptr::null_mut()),
-        }));
-        let mut tail = self.tail.load(Ordering::Relaxed);
-        let mut next;
+        let guard = self.collector.pin();
+        let new_node = match self.pool.pop(&guard) {
+            Some(node) => {
+                unsafe {
+                    (*node).data = MaybeUninit::new(data);
+                    (*node).next = AtomicPtr::new(ptr::null_mut());
+                }
+                node
+            }
+            None => Box::into_raw(Box::new(Node {
+                data: MaybeUninit::new(data),
+                next: AtomicPtr::new(ptr::null_mut()),
+            })),
+        };
+        let mut tail = self.tail.load(Ordering::Acquire);
         loop {
             unsafe {
-                next = (*tail).next.load(Ordering::Relaxed);
+                let next = (*tail).next.load(Ordering::Acquire);
 
                 if next.is_null() {
-                    if (*tail)
-                        .next
-                        .compare_exchange(next, new_node, Ordering::Release, Ordering::Relaxed)
-                        .unwrap()
-                        == next
-                    {
-                        break;
+                    // Publish the new node; `Release` so a thread that
+                    // later `Acquire`-loads this link also sees the data
+                    // written into `new_node` above.
+                    match (*tail).next.compare_exchange_weak(
+                        next,
+                        new_node,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    ) {
+                        Ok(_) => break,
+                        Err(_) => continue,
                     }
                 } else {
-                    self.tail.compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
-                    tail = self.tail.load(Ordering::Relaxed);
+                    // `tail` is stale; help swing it forward before retrying.
+                    let _ = self.tail.compare_exchange_weak(
+                        tail,
+                        next,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    );
+                    tail = self.tail.load(Ordering::Acquire);
                 }
             }
         }
-        self.tail.compare_exchange(tail, new_node, Ordering::Release, Ordering::Relaxed);
+        let _ =
+            self.tail
+                .compare_exchange_weak(tail, new_node, Ordering::AcqRel, Ordering::Acquire);
+        self.notify.notify_one();
     }
 
     pub fn take(&self) -> Option<T> {
-        let mut head = self.head.load(Ordering::Relaxed);
-        let mut next;
+        let guard = self.collector.pin();
+        let mut head = self.head.load(Ordering::Acquire);
         loop {
             unsafe {
-                next = (*head).next.load(Ordering::Relaxed);
+                let next = (*head).next.load(Ordering::Acquire);
 
                 if next.is_null() {
                     return None;
                 }
 
-                if self
-                    .head
-                    .compare_and_swap(head, next, Ordering::Relaxed)
-                    == head
-                {
-                    let node = Box::from_raw(head);
-                    return Some(node.data);
+                match self.head.compare_exchange_weak(
+                    head,
+                    next,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => {
+                        // `next` becomes the new sentinel, so the value
+                        // we're dequeuing has to be read out of it before
+                        // `head` (the old sentinel, now unreachable) is
+                        // recycled.
+                        let data = (*next).data.assume_init_read();
+                        let head_addr = head as usize;
+                        let pool = &self.pool as *const Pool<T> as usize;
+                        guard.defer(move || {
+                            (*(pool as *const Pool<T>)).push(head_addr as *mut Node<T>);
+                        });
+                        return Some(data);
+                    }
+                    Err(current) => head = current,
                 }
-
-                head = self.head.load(Ordering::Relaxed);
             }
         }
     }
 
     pub fn is_empty(&self) -> bool {
-        let head = self.head.load(Ordering::Relaxed);
-        let next = unsafe { (*head).next.load(Ordering::Relaxed) };
+        let _guard = self.collector.pin();
+        let head = self.head.load(Ordering::Acquire);
+        let next = unsafe { (*head).next.load(Ordering::Acquire) };
         next.is_null()
     }
+
+    /// Blocks the calling thread until a value is available, then returns it.
+    ///
+    /// Unlike spinning on `take`, an idle consumer parks and is woken by
+    /// the next `offer`, so it doesn't burn CPU while the queue is empty.
+    pub fn take_blocking(&self) -> T {
+        // Tracks the still-live registration across loop iterations: `park`
+        // is allowed to return spuriously, in which case the token from the
+        // iteration we just parked in is still sitting in `notify`'s queue
+        // and has to be cancelled before we register a new one.
+        let mut token = None;
+        loop {
+            if let Some(data) = self.take() {
+                if let Some(token) = token {
+                    self.notify.cancel(token);
+                }
+                return data;
+            }
+            if let Some(stale) = token.take() {
+                self.notify.cancel(stale);
+            }
+            // Register before the second check below so a notification
+            // racing ahead of this registration can't be missed: either
+            // it arrives after we're registered (and wakes us), or the
+            // data is already visible by the time we re-check.
+            let new_token = self.notify.register_thread();
+            if let Some(data) = self.take() {
+                // Resolved without needing a wake-up; cancel the
+                // registration so it doesn't sit around to steal a
+                // future `notify_one` from an actually-parked consumer.
+                self.notify.cancel(new_token);
+                return data;
+            }
+            token = Some(new_token);
+            std::thread::park();
+        }
+    }
+
+    /// Returns a future that resolves to the next value once one is
+    /// available, registering a `Waker` instead of parking a thread.
+    pub fn take_async(&self) -> TakeFuture<'_, T> {
+        TakeFuture {
+            queue: self,
+            token: None,
+        }
+    }
+}
+
+/// The [`Future`](std::future::Future) returned by [`LockFreeQueue::take_async`].
+pub struct TakeFuture<'q, T> {
+    queue: &'q LockFreeQueue<T>,
+    // The still-live registration from the most recent `Pending` poll, if
+    // any. A future can be polled again after losing a `select!` race or
+    // a timeout, or simply dropped instead of polled again, so this has to
+    // be cancelled explicitly rather than assumed to resolve on its own.
+    token: Option<notify::Token>,
+}
+
+impl<'q, T> std::future::Future for TakeFuture<'q, T> {
+    type Output = T;
+
+    fn poll(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<T> {
+        let this = self.get_mut();
+        if let Some(data) = this.queue.take() {
+            if let Some(token) = this.token.take() {
+                this.queue.notify.cancel(token);
+            }
+            return std::task::Poll::Ready(data);
+        }
+        if let Some(stale) = this.token.take() {
+            // A previous `Pending` poll's registration is still live here
+            // only if this poll was woken some other way (e.g. the
+            // executor re-polling after a timeout); drop it before
+            // registering a fresh one so it doesn't linger.
+            this.queue.notify.cancel(stale);
+        }
+        // Same double-check rationale as `take_blocking`: register the
+        // waker, then re-check so a racing notification is never missed.
+        let token = this.queue.notify.register_waker(cx.waker());
+        match this.queue.take() {
+            Some(data) => {
+                // Resolved without needing a wake-up; cancel the
+                // registration so it doesn't sit around to steal a
+                // future `notify_one` from an actually-pending poll.
+                this.queue.notify.cancel(token);
+                std::task::Poll::Ready(data)
+            }
+            None => {
+                this.token = Some(token);
+                std::task::Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'q, T> Drop for TakeFuture<'q, T> {
+    fn drop(&mut self) {
+        // If this future is dropped while a registration is still live
+        // (e.g. it lost a `select!` race or a timeout fired), cancel it so
+        // it doesn't sit in `notify`'s queue forever waiting to steal a
+        // wake-up meant for some other, still-live waiter.
+        if let Some(token) = self.token.take() {
+            self.queue.notify.cancel(token);
+        }
+    }
 }
 
 impl<T> Drop for LockFreeQueue<T> {
 
     fn drop(&mut self) {
+        // `head` is always the sentinel and never holds a live value, so
+        // its `data` must not be read or dropped; every node after it
+        // does hold a live value that needs dropping explicitly, since
+        // `MaybeUninit<T>` suppresses `Node`'s derived drop glue for it.
         let mut node = self.head.load(Ordering::Relaxed);
-        while node != ptr::null_mut() {
-            let n = unsafe { Box::from_raw(node) };
-            node = n.next.load(Ordering::Relaxed);
+        if node.is_null() {
+            return;
+        }
+        let mut next = unsafe { (*node).next.load(Ordering::Relaxed) };
+        unsafe {
+            drop(Box::from_raw(node));
+        }
+        node = next;
+        while !node.is_null() {
+            unsafe {
+                next = (*node).next.load(Ordering::Relaxed);
+                let mut owned = Box::from_raw(node);
+                owned.data.assume_init_drop();
+            }
+            node = next;
         }
     }
 }
 
+// `LockFreeQueue` only ever exposes a `T` value to one thread at a time
+// (it moves in via `offer` and back out via `take`/`take_blocking`/
+// `take_async`), and all of its internal pointer traffic is synchronized
+// through the atomics and epoch reclamation above, so it's safe to send
+// and share across threads whenever `T` is `Send`.
+unsafe impl<T: Send> Send for LockFreeQueue<T> {}
+unsafe impl<T: Send> Sync for LockFreeQueue<T> {}
+
+/// A single-slot building block that atomically swaps an `Option<Box<T>>`
+/// in or out without ever cloning the value it holds.
+///
+/// `Pool` uses this as the fast path for the top of its free-list: an
+/// unconditional swap needs no CAS-retry loop, which is exactly the
+/// trade-off that fits a single-slot cache. The queue's own head/tail
+/// links still need a real compare-and-swap (retry only on contention) to
+/// stay lock-free, so those keep manipulating their raw `AtomicPtr`s
+/// directly.
+pub struct AtomicOptionBox<T> {
+    ptr: AtomicPtr<T>,
+}
+
+impl<T> AtomicOptionBox<T> {
+    /// Creates a slot holding `value`.
+    pub fn new(value: Option<Box<T>>) -> Self {
+        AtomicOptionBox {
+            ptr: AtomicPtr::new(Self::into_raw(value)),
+        }
+    }
+
+    fn into_raw(value: Option<Box<T>>) -> *mut T {
+        value.map_or(ptr::null_mut(), Box::into_raw)
+    }
+
+    unsafe fn from_raw(ptr: *mut T) -> Option<Box<T>> {
+        if ptr.is_null() {
+            None
+        } else {
+            Some(unsafe { Box::from_raw(ptr) })
+        }
+    }
+
+    /// Atomically replaces the stored value with `new`, handing back
+    /// whatever was stored before — ownership transfers both ways
+    /// without either value being cloned.
+    pub fn swap(&self, new: Option<Box<T>>) -> Option<Box<T>> {
+        let old = self.ptr.swap(Self::into_raw(new), Ordering::AcqRel);
+        unsafe { Self::from_raw(old) }
+    }
+
+    /// Atomically takes the stored value out, leaving the slot empty.
+    pub fn take(&self) -> Option<Box<T>> {
+        self.swap(None)
+    }
+}
+
+impl<T> Default for AtomicOptionBox<T> {
+    fn default() -> Self {
+        AtomicOptionBox::new(None)
+    }
+}
+
+unsafe impl<T: Send> Send for AtomicOptionBox<T> {}
+unsafe impl<T: Send> Sync for AtomicOptionBox<T> {}
+
+impl<T> Drop for AtomicOptionBox<T> {
+    fn drop(&mut self) {
+        let ptr = *self.ptr.get_mut();
+        if !ptr.is_null() {
+            unsafe {
+                drop(Box::from_raw(ptr));
+            }
+        }
+    }
+}
+
+/// A single slot in a [`BoundedQueue`]'s ring buffer.
+///
+/// `stamp` encodes which generation of push/pop the slot is ready for,
+/// following Dmitry Vyukov's bounded MPMC queue: a slot at index `i` starts
+/// life stamped `i`, is stamped `i + 1` once a value has been pushed into
+/// it, and is stamped `i + capacity` once that value has been popped back
+/// out (making it ready for the next lap around the ring).
+struct Slot<T> {
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A bounded multi-producer multi-consumer queue backed by a fixed-size
+/// ring buffer (Vyukov's array-based MPMC algorithm).
+///
+/// Unlike [`LockFreeQueue`], which grows without limit, `BoundedQueue` has a
+/// fixed `capacity` fixed at construction time: `push` fails with `Err` once
+/// the queue is full instead of allocating, giving callers backpressure.
+#[derive(Debug)]
+pub struct BoundedQueue<T> {
+    buffer: Box<[Slot<T>]>,
+    mask: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl<T> std::fmt::Debug for Slot<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Slot")
+            .field("stamp", &self.stamp.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl<T> BoundedQueue<T> {
+    /// Creates a queue that can hold up to `capacity` elements.
+    ///
+    /// `capacity` must be a power of two so that slot indices can be
+    /// computed with a bitmask instead of a modulo.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be non-zero");
+        assert!(capacity.is_power_of_two(), "capacity must be a power of two");
+
+        let buffer = (0..capacity)
+            .map(|i| Slot {
+                stamp: AtomicUsize::new(i),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+
+        BoundedQueue {
+            buffer,
+            mask: capacity - 1,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// The fixed number of elements this queue can hold at once.
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Pushes `value` onto the queue, returning it back in `Err` if the
+    /// queue is currently full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.buffer[tail & self.mask];
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            let diff = stamp as isize - tail as isize;
+
+            if diff == 0 {
+                // The slot is ready for this tail generation; claim it.
+                match self.tail.compare_exchange_weak(
+                    tail,
+                    tail.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe {
+                            (*slot.value.get()).write(value);
+                        }
+                        slot.stamp.store(tail.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => tail = current,
+                }
+            } else if diff < 0 {
+                // The slot hasn't been drained from the previous lap yet.
+                return Err(value);
+            } else {
+                tail = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Pops the oldest value from the queue, or returns `None` if it is
+    /// currently empty.
+    pub fn pop(&self) -> Option<T> {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.buffer[head & self.mask];
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            let diff = stamp as isize - head.wrapping_add(1) as isize;
+
+            if diff == 0 {
+                // The slot holds a value pushed for this head generation.
+                match self.head.compare_exchange_weak(
+                    head,
+                    head.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.stamp
+                            .store(head.wrapping_add(self.buffer.len()), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => head = current,
+                }
+            } else if diff < 0 {
+                // The slot hasn't been filled for this head generation yet.
+                return None;
+            } else {
+                head = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for BoundedQueue<T> {}
+unsafe impl<T: Send> Sync for BoundedQueue<T> {}
+
+impl<T> Drop for BoundedQueue<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::LockFreeQueue;
+    use crate::{BoundedQueue, LockFreeQueue};
 
     #[test]
     fn test_is_empty() {
@@ -130,9 +973,394 @@ mod tests {
         queue.offer(1);
         queue.offer(2);
 
-        assert_eq!(queue.take(), Some(0)); // TODO this dummy element
         assert_eq!(queue.take(), Some(1));
         assert_eq!(queue.take(), Some(2));
         assert_eq!(queue.take(), None);
     }
+
+    #[test]
+    fn test_offer_take_non_default_type() {
+        let queue: LockFreeQueue<String> = LockFreeQueue::new();
+
+        queue.offer("hello".to_string());
+        queue.offer("world".to_string());
+
+        assert_eq!(queue.take(), Some("hello".to_string()));
+        assert_eq!(queue.take(), Some("world".to_string()));
+        assert_eq!(queue.take(), None);
+    }
+
+    #[test]
+    fn test_bounded_push_pop() {
+        let queue = BoundedQueue::new(4);
+
+        assert_eq!(queue.push(1), Ok(()));
+        assert_eq!(queue.push(2), Ok(()));
+
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_bounded_rejects_push_when_full() {
+        let queue = BoundedQueue::new(2);
+
+        assert_eq!(queue.push(1), Ok(()));
+        assert_eq!(queue.push(2), Ok(()));
+        assert_eq!(queue.push(3), Err(3));
+
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.push(3), Ok(()));
+    }
+
+    #[test]
+    fn test_concurrent_offer_take_does_not_corrupt_data() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let queue = Arc::new(LockFreeQueue::new());
+        let producers: Vec<_> = (0..4)
+            .map(|t| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..500 {
+                        queue.offer(t * 1000 + i);
+                    }
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    let mut taken = Vec::new();
+                    while let Some(value) = queue.take() {
+                        taken.push(value);
+                    }
+                    taken
+                })
+            })
+            .collect();
+
+        let mut taken: Vec<_> = consumers
+            .into_iter()
+            .flat_map(|consumer| consumer.join().unwrap())
+            .collect();
+        let total = taken.len();
+        assert_eq!(total, 4 * 500);
+        taken.sort_unstable();
+        let expected: Vec<_> = (0..4)
+            .flat_map(|t| (0..500).map(move |i| t * 1000 + i))
+            .collect();
+        assert_eq!(taken, expected);
+    }
+
+    #[test]
+    fn test_concurrent_is_empty_during_offer_take_does_not_crash() {
+        use std::sync::Arc;
+        use std::thread;
+
+        // Regression test: `is_empty` used to dereference `head` without
+        // pinning the epoch collector, racing a concurrent `take`'s node
+        // recycling. Hammer it alongside producers/consumers so a
+        // use-after-free would show up as a crash or corrupted read.
+        let queue = Arc::new(LockFreeQueue::new());
+        let producers: Vec<_> = (0..4)
+            .map(|t| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..2000 {
+                        queue.offer(t * 10_000 + i);
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    let mut taken = 0;
+                    while taken < 2000 {
+                        if queue.take().is_some() {
+                            taken += 1;
+                        }
+                    }
+                })
+            })
+            .collect();
+        let checkers: Vec<_> = (0..4)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for _ in 0..20_000 {
+                        let _ = queue.is_empty();
+                    }
+                })
+            })
+            .collect();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        for consumer in consumers {
+            consumer.join().unwrap();
+        }
+        for checker in checkers {
+            checker.join().unwrap();
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_bounded_capacity() {
+        let queue: BoundedQueue<i32> = BoundedQueue::new(8);
+        assert_eq!(queue.capacity(), 8);
+    }
+
+    #[test]
+    fn test_concurrent_bounded_push_pop_does_not_corrupt_data() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        const PER_PRODUCER: usize = 500;
+        const PRODUCERS: usize = 4;
+        const TOTAL: usize = PRODUCERS * PER_PRODUCER;
+
+        let queue = Arc::new(BoundedQueue::new(16));
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|t| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..PER_PRODUCER {
+                        let mut value = t * 1000 + i;
+                        while let Err(rejected) = queue.push(value) {
+                            value = rejected;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // Consumers race against a shared total rather than a fixed
+        // per-consumer quota: under scheduler skew, one consumer can
+        // easily take far more or fewer than `TOTAL / 4`, and a fixed
+        // quota per consumer risks it starving out on its own miss budget
+        // while slower producers/consumers are still catching up.
+        let claimed = Arc::new(AtomicUsize::new(0));
+        let consumers: Vec<_> = (0..PRODUCERS)
+            .map(|_| {
+                let queue = queue.clone();
+                let claimed = claimed.clone();
+                thread::spawn(move || {
+                    let mut taken = Vec::new();
+                    while claimed.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                        (n < TOTAL).then_some(n + 1)
+                    }) != Err(TOTAL)
+                    {
+                        if let Some(value) = queue.pop() {
+                            taken.push(value);
+                        } else {
+                            // Another consumer already claimed the slot
+                            // this pop would have filled; give it back.
+                            claimed.fetch_sub(1, Ordering::SeqCst);
+                            thread::yield_now();
+                        }
+                    }
+                    taken
+                })
+            })
+            .collect();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        let mut taken: Vec<_> = consumers
+            .into_iter()
+            .flat_map(|consumer| consumer.join().unwrap())
+            .collect();
+        let total = taken.len();
+        assert_eq!(total, TOTAL);
+        taken.sort_unstable();
+        let expected: Vec<_> = (0..PRODUCERS)
+            .flat_map(|t| (0..PER_PRODUCER).map(move |i| t * 1000 + i))
+            .collect();
+        assert_eq!(taken, expected);
+    }
+
+    #[test]
+    fn test_take_blocking_wakes_on_offer() {
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+
+        let queue = Arc::new(LockFreeQueue::new());
+        let consumer = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.take_blocking())
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        queue.offer(7);
+
+        assert_eq!(consumer.join().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_notify_cancel_prevents_stale_registration_from_stealing_wakeup() {
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+
+        let queue = Arc::new(LockFreeQueue::new());
+
+        // Mirrors what `take_blocking`/`TakeFuture::poll` now do when
+        // their own double-check resolves without needing to park/poll:
+        // register, then immediately cancel. Without the cancel, this
+        // registration would sit at the front of the FIFO forever and
+        // silently eat the wake-up the consumer below actually needs.
+        let token = queue.notify.register_thread();
+        queue.notify.cancel(token);
+
+        let consumer = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.take_blocking())
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        queue.offer(7);
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(consumer.is_finished());
+        assert_eq!(consumer.join().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_take_blocking_cancels_stale_token_on_spurious_wakeup() {
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+
+        let queue = Arc::new(LockFreeQueue::new());
+        let consumer = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.take_blocking())
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(queue.notify.waiter_count(), 1);
+
+        // Unparking directly (instead of via `notify_one`) mirrors a
+        // spurious wakeup: the consumer resumes with its registration
+        // still sitting in `notify`'s queue. It should cancel that stale
+        // token before registering a fresh one, not leak it alongside the
+        // new one.
+        consumer.thread().unpark();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(queue.notify.waiter_count(), 1);
+
+        queue.offer(99);
+        assert_eq!(consumer.join().unwrap(), 99);
+    }
+
+    #[test]
+    fn test_dropping_pending_take_future_cancels_its_registration() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Poll, Waker};
+
+        let queue = LockFreeQueue::<i32>::new();
+        let mut future = queue.take_async();
+        let waker = Waker::noop();
+        let mut cx = Context::from_waker(waker);
+
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+        assert_eq!(queue.notify.waiter_count(), 1);
+
+        drop(future);
+        assert_eq!(queue.notify.waiter_count(), 0);
+    }
+
+    #[test]
+    fn test_take_async_resolves_once_data_is_offered() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Poll, Waker};
+
+        let queue = LockFreeQueue::new();
+        let mut future = queue.take_async();
+        let waker = Waker::noop();
+        let mut cx = Context::from_waker(waker);
+
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+
+        queue.offer(42);
+
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Ready(42));
+    }
+
+    #[test]
+    fn test_lock_free_queue_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<LockFreeQueue<i32>>();
+    }
+
+    #[test]
+    fn test_epoch_registration_survives_collector_reuse_at_same_address() {
+        // Regression test for a bug where the per-thread epoch cache was
+        // keyed by the `Collector`'s address: dropping one queue and
+        // creating another can reuse the same stack/heap slot, which used
+        // to produce a cache hit for a *different* collector's stale
+        // registration. Repeatedly drop-and-recreate a queue on this
+        // thread and confirm every instance still observes its own
+        // offers/takes correctly.
+        for i in 0..64 {
+            let queue = LockFreeQueue::new();
+            queue.offer(i);
+            assert_eq!(queue.take(), Some(i));
+            assert!(queue.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_dropping_queue_prunes_its_epoch_registration() {
+        use crate::epoch::registered_thread_count;
+
+        let before = registered_thread_count();
+        for _ in 0..16 {
+            let queue = LockFreeQueue::new();
+            queue.offer(1);
+            assert_eq!(queue.take(), Some(1));
+            // Dropped here; its registration should be pruned by the next
+            // `local()` call on this thread rather than accumulating.
+        }
+        // One fresh registration for the queue created just below, plus
+        // whatever pre-existed — none of the 16 dropped above should have
+        // survived.
+        let queue = LockFreeQueue::new();
+        queue.offer(1);
+        assert_eq!(queue.take(), Some(1));
+        assert_eq!(registered_thread_count(), before + 1);
+    }
+
+    #[test]
+    fn test_atomic_option_box_swap_transfers_ownership() {
+        use crate::AtomicOptionBox;
+
+        let slot = AtomicOptionBox::new(Some(Box::new(1)));
+
+        let old = slot.swap(Some(Box::new(2)));
+        assert_eq!(old, Some(Box::new(1)));
+
+        let old = slot.take();
+        assert_eq!(old, Some(Box::new(2)));
+        assert_eq!(slot.take(), None);
+    }
 }
\ No newline at end of file